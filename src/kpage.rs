@@ -0,0 +1,149 @@
+//! Readers for `/proc/kpageflags` and `/proc/kpagecount`. Both files are indexed identically to
+//! `/proc/[pid]/pagemap` (8 bytes per PFN), so they reuse the same seek-by-index pattern as
+//! `PageMap::get_by_vaddr`, just keyed by PFN instead of vaddr.
+
+use std::fs::File;
+use std::io::{self, BufReader, Read, Seek, SeekFrom};
+
+// KPF_* bits, from Linux's `Documentation/admin-guide/mm/pagemap.rst`.
+pub const KPF_LOCKED: u64 = 1 << 0;
+pub const KPF_SLAB: u64 = 1 << 7;
+pub const KPF_BUDDY: u64 = 1 << 10;
+pub const KPF_LRU: u64 = 1 << 5;
+pub const KPF_ANON: u64 = 1 << 12;
+pub const KPF_COMPOUND_HEAD: u64 = 1 << 15;
+pub const KPF_COMPOUND_TAIL: u64 = 1 << 16;
+pub const KPF_HUGE: u64 = 1 << 17;
+pub const KPF_THP: u64 = 1 << 22;
+pub const KPF_ZERO_PAGE: u64 = 1 << 24;
+
+/// The flags for a single physical page, as read from `/proc/kpageflags`.
+#[derive(Copy, Clone, Eq, PartialEq, Hash, Debug)]
+#[repr(C)]
+pub struct PageFlags(u64);
+
+impl PageFlags {
+    /// Is the page locked in memory (e.g. for I/O)?
+    pub fn locked(self) -> bool {
+        self.0 & KPF_LOCKED != 0
+    }
+
+    /// Is the page part of the slab allocator?
+    pub fn slab(self) -> bool {
+        self.0 & KPF_SLAB != 0
+    }
+
+    /// Is the page free, sitting in the buddy allocator's free lists?
+    pub fn buddy(self) -> bool {
+        self.0 & KPF_BUDDY != 0
+    }
+
+    /// Is the page on an LRU list?
+    pub fn lru(self) -> bool {
+        self.0 & KPF_LRU != 0
+    }
+
+    /// Is the page anonymous memory (as opposed to file-backed)?
+    pub fn anon(self) -> bool {
+        self.0 & KPF_ANON != 0
+    }
+
+    /// Is this the first page of a compound (multi-order) page?
+    pub fn compound_head(self) -> bool {
+        self.0 & KPF_COMPOUND_HEAD != 0
+    }
+
+    /// Is this a non-first page of a compound (multi-order) page?
+    pub fn compound_tail(self) -> bool {
+        self.0 & KPF_COMPOUND_TAIL != 0
+    }
+
+    /// Is the page part of a hugetlbfs huge page?
+    pub fn huge(self) -> bool {
+        self.0 & KPF_HUGE != 0
+    }
+
+    /// Is the page part of a transparent huge page?
+    pub fn thp(self) -> bool {
+        self.0 & KPF_THP != 0
+    }
+
+    /// Is the page the shared, read-only zero page?
+    pub fn zero_page(self) -> bool {
+        self.0 & KPF_ZERO_PAGE != 0
+    }
+
+    /// Is the page part of a compound page at all (head or tail)?
+    pub fn compound(self) -> bool {
+        self.compound_head() || self.compound_tail()
+    }
+}
+
+/// A reader for `/proc/kpageflags`, which reports the `PageFlags` for every PFN on the system.
+pub struct KPageFlags {
+    file: BufReader<File>,
+}
+
+impl KPageFlags {
+    pub fn open() -> io::Result<Self> {
+        Ok(KPageFlags {
+            file: BufReader::new(File::open("/proc/kpageflags")?),
+        })
+    }
+
+    /// Get the `PageFlags` for the given PFN (as returned by `SinglePageData::pfn`).
+    pub fn get(&mut self, pfn: u64) -> io::Result<PageFlags> {
+        Ok(PageFlags(read_u64_at(&mut self.file, pfn)?))
+    }
+
+    /// Get the `PageFlags` for every PFN in `[start, end]` (inclusive) in one seek+read, mirroring
+    /// `PageMap::get_by_range`.
+    pub fn get_by_range(&mut self, start: u64, end: u64) -> io::Result<Vec<PageFlags>> {
+        Ok(read_u64_range(&mut self.file, start, end)?
+            .into_iter()
+            .map(PageFlags)
+            .collect())
+    }
+}
+
+/// A reader for `/proc/kpagecount`, which reports how many times each PFN on the system is
+/// mapped (0 for free pages, >1 for pages shared between multiple mappings).
+pub struct KPageCount {
+    file: BufReader<File>,
+}
+
+impl KPageCount {
+    pub fn open() -> io::Result<Self> {
+        Ok(KPageCount {
+            file: BufReader::new(File::open("/proc/kpagecount")?),
+        })
+    }
+
+    /// Get the map count for the given PFN (as returned by `SinglePageData::pfn`).
+    pub fn get(&mut self, pfn: u64) -> io::Result<u64> {
+        read_u64_at(&mut self.file, pfn)
+    }
+
+    /// Get the map count for every PFN in `[start, end]` (inclusive) in one seek+read, mirroring
+    /// `PageMap::get_by_range`.
+    pub fn get_by_range(&mut self, start: u64, end: u64) -> io::Result<Vec<u64>> {
+        read_u64_range(&mut self.file, start, end)
+    }
+}
+
+fn read_u64_at(file: &mut BufReader<File>, pfn: u64) -> io::Result<u64> {
+    let mut data = [0u8; 8];
+    file.seek(SeekFrom::Start(pfn * 8))?;
+    file.read_exact(&mut data)?;
+    Ok(u64::from_ne_bytes(data))
+}
+
+/// Read the u64 entries for every PFN in `[start, end]` (inclusive) in one seek+read.
+fn read_u64_range(file: &mut BufReader<File>, start: u64, end: u64) -> io::Result<Vec<u64>> {
+    let count = (end - start + 1) as usize;
+    let mut data = vec![0u8; count * 8];
+    file.seek(SeekFrom::Start(start * 8))?;
+    file.read_exact(&mut data)?;
+
+    Ok(data.chunks_exact(8).map(|c| u64::from_ne_bytes(c.try_into().unwrap())).collect())
+}
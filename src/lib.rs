@@ -1,13 +1,53 @@
 //! A utility for reading `/proc/[pid]/pagemap` to produce a profile for eager paging.
 
+mod kpage;
+mod maps;
+
+pub use kpage::{KPageCount, KPageFlags, PageFlags};
+pub use maps::Region;
+
 use std::fs::File;
-use std::io::{BufReader, Read, Seek, SeekFrom};
+use std::io::{self, BufReader, Read, Seek, SeekFrom, Write};
+use std::os::unix::io::AsRawFd;
 
 pub const PAGE_SHIFT: usize = 12;
 pub const PAGE_SIZE: usize = 1 << PAGE_SHIFT;
 
 pub const VSYSCALL_SECTION_START: u64 = 0xffffffffff600000;
 
+/// The largest buddy order the kernel's page allocator hands out (`MAX_ORDER` in
+/// `include/linux/mmzone.h`), i.e. blocks of up to `2^MAX_ORDER` pages (4 MiB).
+///
+/// 1 GiB hugetlbfs pages (order 18) are out of scope for a buddy-order histogram: they're carved
+/// out of CMA or reserved at boot, not assembled from the normal buddy free lists, so they never
+/// appear as a buddy-allocator block no matter how high this constant is raised.
+pub const MAX_ORDER: u32 = 10;
+
+/// Greedily decompose a maximal run of contiguous PFNs `[start, end]` (inclusive) into
+/// power-of-two, self-aligned blocks, largest first, exactly as the kernel's buddy allocator would
+/// view the same physical memory. Returns the order (`log2` of the block size in pages) of each
+/// block, in the order they appear starting from `start`.
+pub fn decompose_into_buddy_orders(start: u64, end: u64) -> Vec<u32> {
+    let mut pfn = start;
+    let mut remaining = end - start + 1;
+    let mut orders = Vec::new();
+
+    while remaining > 0 {
+        let mut order = MAX_ORDER;
+        while order > 0 && (1u64 << order > remaining || pfn % (1u64 << order) != 0) {
+            order -= 1;
+        }
+
+        orders.push(order);
+
+        let size = 1u64 << order;
+        pfn += size;
+        remaining -= size;
+    }
+
+    orders
+}
+
 // A bunch of constants from Linux 4.15 (probably valid on other versions)...
 pub const PAGEMAP_PRESENT_MASK: u64 = 1 << 63;
 pub const PAGEMAP_SWAP_MASK: u64 = 1 << 62;
@@ -49,14 +89,116 @@ impl SinglePageData {
         self.0 & PAGEMAP_SOFT_DIRTY_MASK != 0
     }
 
-    /// The page frame number of the physical page backing this virtual page if the page is present
-    /// in RAM. Otherwise, if the page is swapped out, then bits 4-0 indicate swap type (i.e.,
-    /// which swap space), and bits 54-5 indicate the swap slot on the swap space.
-    pub fn pfn(self) -> u64 {
-        self.0 & PAGEMAP_PFN_MASK
+    /// The page frame number of the physical page backing this virtual page, if the page is
+    /// present in RAM. (If the page is swapped out, the same bits hold the swap type and slot
+    /// instead; see `swap_type`/`swap_offset`.)
+    ///
+    /// Returns `None` if the page isn't present, or if the PFN field reads back as zero, which
+    /// per the kernel happens both when the page isn't present and when the caller lacks
+    /// `CAP_SYS_ADMIN` (the kernel zeroes the field rather than rejecting the read). Callers that
+    /// need to tell "not present" apart from "hidden" should check `present()` as well.
+    pub fn pfn(self) -> Option<u64> {
+        if !self.present() {
+            return None;
+        }
+
+        match self.0 & PAGEMAP_PFN_MASK {
+            0 => None,
+            pfn => Some(pfn),
+        }
+    }
+
+    /// If this page is swapped out, the swap type (i.e., which swap space it lives on): bits 4:0
+    /// of the raw pagemap entry. `None` if the page isn't swapped.
+    pub fn swap_type(self) -> Option<u8> {
+        if !self.swap() {
+            return None;
+        }
+
+        Some((self.0 & 0x1f) as u8)
+    }
+
+    /// If this page is swapped out, its slot on that swap space: bits 54:5 of the raw pagemap
+    /// entry. `None` if the page isn't swapped.
+    pub fn swap_offset(self) -> Option<u64> {
+        if !self.swap() {
+            return None;
+        }
+
+        Some((self.0 >> 5) & ((1 << 50) - 1))
+    }
+
+    /// Where this page's data currently lives: resident in RAM, swapped out, or not mapped to
+    /// anything at all.
+    pub fn location(self) -> PageLocation {
+        if self.present() {
+            PageLocation::Present(self.0 & PAGEMAP_PFN_MASK)
+        } else if self.swap() {
+            PageLocation::Swapped {
+                type_: self.swap_type().unwrap(),
+                offset: self.swap_offset().unwrap(),
+            }
+        } else {
+            PageLocation::NotMapped
+        }
+    }
+
+    /// Compute the full `PAGE_IS_*` category bitmask for this page, as far as derivable from a
+    /// single pagemap entry. Used by the `clear_refs`-based fallback of `PageMap::scan_dirty` to
+    /// emulate the category filtering/reporting that the `PAGEMAP_SCAN` ioctl does in-kernel.
+    ///
+    /// `PAGE_IS_HUGE` and `PAGE_IS_WPALLOWED` aren't derivable this way (they need
+    /// `/proc/kpageflags` and VMA data, respectively), so they're never set here.
+    fn categories(self) -> u64 {
+        let mut categories = 0;
+
+        if self.soft_dirty() {
+            categories |= PAGE_IS_WRITTEN | PAGE_IS_SOFT_DIRTY;
+        }
+
+        if self.file_backed() {
+            categories |= PAGE_IS_FILE;
+        }
+
+        if self.present() {
+            categories |= PAGE_IS_PRESENT;
+
+            if self.0 & PAGEMAP_PFN_MASK == 0 {
+                categories |= PAGE_IS_PFNZERO;
+            }
+        }
+
+        if self.swap() {
+            categories |= PAGE_IS_SWAPPED;
+        }
+
+        categories
+    }
+
+    /// Does this page have *every* category bit set in `mask`? The kernel's `PAGEMAP_SCAN`
+    /// `category_mask` is AND semantics (a page must have all requested bits to match);
+    /// `category_anyof_mask` is the OR one, which this crate doesn't expose.
+    fn matches_categories(self, mask: u64) -> bool {
+        self.categories() & mask == mask
     }
 }
 
+/// Where a virtual page's data currently lives, as decoded from a `SinglePageData`.
+///
+/// `present()`/`swap()` share the same bit field for two different meanings (see
+/// `SinglePageData::pfn`), so this is the one-stop way to tell which interpretation applies
+/// without juggling both raw accessors yourself.
+#[derive(Copy, Clone, Eq, PartialEq, Hash, Debug)]
+pub enum PageLocation {
+    /// Resident in RAM at the given PFN (`0` if hidden because the reader lacks
+    /// `CAP_SYS_ADMIN`; see `SinglePageData::pfn`).
+    Present(u64),
+    /// Swapped out to the given (type, offset) slot on a swap space.
+    Swapped { type_: u8, offset: u64 },
+    /// Not mapped to anything (neither resident nor swapped out).
+    NotMapped,
+}
+
 impl std::fmt::Display for SinglePageData {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(
@@ -67,7 +209,10 @@ impl std::fmt::Display for SinglePageData {
             if self.file_backed() { "F" } else { "-" },
             if self.exclusive() { "X" } else { "-" },
             if self.soft_dirty() { "D" } else { "-" },
-            self.pfn()
+            match self.pfn() {
+                Some(pfn) => pfn.to_string(),
+                None => "?".to_owned(),
+            }
         )
     }
 }
@@ -75,15 +220,35 @@ impl std::fmt::Display for SinglePageData {
 /// The contents of `/proc/[pid]/pagemap` in a seekable way.
 pub struct PageMap {
     file: BufReader<File>,
+    /// The process this pagemap belongs to, used to find the adjacent `maps` and `clear_refs`
+    /// files when needed.
+    pid: u32,
 }
 
 impl PageMap {
+    /// Wrap an already-open `/proc/self/pagemap` file for the calling process. The `maps` and
+    /// `clear_refs` files used by `regions()`/`scan_dirty()` are derived from the calling
+    /// process's pid, so `file` must be the calling process's own pagemap; use `PageMap::for_pid`
+    /// to profile a different process.
     pub fn new(file: File) -> Self {
         PageMap {
             file: BufReader::new(file),
+            pid: std::process::id(),
         }
     }
 
+    /// Open `/proc/[pid]/pagemap` for the given process. This is the entry point for profiling a
+    /// process other than the one calling this function; seeing real PFNs requires
+    /// `CAP_SYS_ADMIN`, even for the calling process's own pagemap.
+    pub fn for_pid(pid: u32) -> io::Result<Self> {
+        let file = File::open(format!("/proc/{}/pagemap", pid))?;
+
+        Ok(PageMap {
+            file: BufReader::new(file),
+            pid,
+        })
+    }
+
     /// Get the `SinglePageData` for the page starting at the given address.
     pub fn get_by_vaddr(&mut self, vaddr: u64) -> std::io::Result<SinglePageData> {
         // Sanity check
@@ -129,4 +294,232 @@ impl PageMap {
 
         Ok(data)
     }
+
+    /// List the mapped regions of this pagemap's process, by parsing `/proc/[pid]/maps`.
+    pub fn regions(&self) -> io::Result<Vec<Region>> {
+        maps::regions_for_pid(self.pid)
+    }
+
+    /// Scan `[start, end)` for pages touched since the last scan (or since soft-dirty was last
+    /// cleared), using the `PAGEMAP_SCAN` ioctl when the kernel supports it, and falling back to
+    /// the older clear_refs-then-reread dance otherwise.
+    pub fn scan_dirty(
+        &mut self,
+        start: u64,
+        end: u64,
+        opts: DirtyScan,
+    ) -> io::Result<DirtyScanResult> {
+        assert!(start % (PAGE_SIZE as u64) == 0, "Range is not page-aligned");
+        assert!(end % (PAGE_SIZE as u64) == 0, "Range is not page-aligned");
+
+        match self.scan_dirty_ioctl(start, end, &opts) {
+            Ok(result) => Ok(result),
+            Err(_) => self.scan_dirty_clear_refs(start, end, &opts),
+        }
+    }
+
+    /// Fast path: ask the kernel to do the clear+scan in one syscall via `PAGEMAP_SCAN`.
+    fn scan_dirty_ioctl(
+        &mut self,
+        start: u64,
+        end: u64,
+        opts: &DirtyScan,
+    ) -> io::Result<DirtyScanResult> {
+        let max_pages = if opts.max_pages == 0 {
+            (end - start) >> PAGE_SHIFT
+        } else {
+            opts.max_pages
+        };
+
+        let mut vec = vec![PageRegion::default(); max_pages as usize];
+
+        let mut arg = PmScanArg {
+            size: std::mem::size_of::<PmScanArg>() as u64,
+            flags: if opts.clear { PM_SCAN_WP_MATCHING } else { 0 },
+            start,
+            end,
+            walk_end: 0,
+            vec: vec.as_mut_ptr() as u64,
+            vec_len: vec.len() as u64,
+            max_pages,
+            category_inverted: 0,
+            category_mask: opts.categories_mask,
+            category_anyof_mask: 0,
+            return_mask: opts.return_mask,
+        };
+
+        let fd = self.file.get_ref().as_raw_fd();
+        let ret = unsafe { libc::ioctl(fd, PAGEMAP_SCAN, &mut arg as *mut PmScanArg) };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let mut dirty_pages = Vec::new();
+        for region in vec.iter().take(ret as usize) {
+            let mut vaddr = region.start;
+            while vaddr < region.end {
+                dirty_pages.push(DirtyPage {
+                    vaddr,
+                    categories: region.categories,
+                });
+                vaddr += PAGE_SIZE as u64;
+            }
+        }
+
+        Ok(DirtyScanResult {
+            pages_scanned: (arg.walk_end - start) >> PAGE_SHIFT,
+            dirty_pages,
+        })
+    }
+
+    /// Fallback path: read the range and collect whatever matches `opts`, then (if `opts.clear`)
+    /// reset soft-dirty via `clear_refs`, so the next call sees the pages the caller's workload
+    /// touched in between.
+    fn scan_dirty_clear_refs(
+        &mut self,
+        start: u64,
+        end: u64,
+        opts: &DirtyScan,
+    ) -> io::Result<DirtyScanResult> {
+        // Read the range first, to capture what was dirtied since the *last* clear, before we
+        // rearm soft-dirty for the next call below. Clearing up front would wipe the very bits
+        // we're about to read, so every scan would report ~zero dirty pages.
+        let pages = self.get_by_range(start, end)?;
+
+        let mut dirty_pages = Vec::new();
+        let max_pages = if opts.max_pages == 0 {
+            u64::MAX
+        } else {
+            opts.max_pages
+        };
+
+        let mut pages_scanned = pages.len() as u64;
+        for (i, page) in pages.iter().enumerate() {
+            if dirty_pages.len() as u64 >= max_pages {
+                pages_scanned = i as u64;
+                break;
+            }
+
+            if !page.matches_categories(opts.categories_mask) {
+                continue;
+            }
+
+            dirty_pages.push(DirtyPage {
+                vaddr: start + (i as u64) * (PAGE_SIZE as u64),
+                categories: page.categories() & opts.return_mask,
+            });
+        }
+
+        if opts.clear {
+            let mut clear_refs = File::create(format!("/proc/{}/clear_refs", self.pid))?;
+            clear_refs.write_all(b"4\n")?;
+        }
+
+        Ok(DirtyScanResult {
+            pages_scanned,
+            dirty_pages,
+        })
+    }
+}
+
+// Category bits accepted by `DirtyScan::categories_mask`/`return_mask`, matching the `PAGE_IS_*`
+// bits of the `PAGEMAP_SCAN` ioctl (see Linux's `Documentation/admin-guide/mm/pagemap.rst`).
+pub const PAGE_IS_WPALLOWED: u64 = 1 << 0;
+pub const PAGE_IS_WRITTEN: u64 = 1 << 1;
+pub const PAGE_IS_FILE: u64 = 1 << 2;
+pub const PAGE_IS_PRESENT: u64 = 1 << 3;
+pub const PAGE_IS_SWAPPED: u64 = 1 << 4;
+pub const PAGE_IS_PFNZERO: u64 = 1 << 5;
+pub const PAGE_IS_HUGE: u64 = 1 << 6;
+pub const PAGE_IS_SOFT_DIRTY: u64 = 1 << 7;
+
+// `pm_scan_arg::flags` bit telling the kernel to clear soft-dirty as it scans.
+const PM_SCAN_WP_MATCHING: u64 = 1 << 0;
+
+/// Options for `PageMap::scan_dirty`, mirroring the fields of the kernel's `PAGEMAP_SCAN` ioctl.
+#[derive(Copy, Clone, Debug)]
+pub struct DirtyScan {
+    /// Stop collecting after this many matching pages. `0` means no cap.
+    pub max_pages: u64,
+    /// Only pages having *all* of these `PAGE_IS_*` bits set are collected (AND semantics,
+    /// mirroring the kernel's `category_mask`; there's no support for the OR-semantics
+    /// `category_anyof_mask`).
+    pub categories_mask: u64,
+    /// Which `PAGE_IS_*` bits to report back in `DirtyPage::categories` for each collected page.
+    pub return_mask: u64,
+    /// Re-arm soft-dirty tracking for the scanned range as we go, so the next scan only sees
+    /// pages touched after this one. The `clear_refs` fallback re-arms the whole process, not
+    /// just the scanned range.
+    pub clear: bool,
+}
+
+impl Default for DirtyScan {
+    fn default() -> Self {
+        DirtyScan {
+            max_pages: 0,
+            categories_mask: PAGE_IS_SOFT_DIRTY | PAGE_IS_WRITTEN,
+            return_mask: PAGE_IS_SOFT_DIRTY | PAGE_IS_WRITTEN,
+            clear: true,
+        }
+    }
 }
+
+/// A page reported by `PageMap::scan_dirty`.
+#[derive(Copy, Clone, Eq, PartialEq, Hash, Debug, Default)]
+pub struct DirtyPage {
+    /// The vaddr of the page.
+    pub vaddr: u64,
+    /// Whichever of `DirtyScan::return_mask`'s `PAGE_IS_*` bits this page actually has set.
+    pub categories: u64,
+}
+
+/// The result of a `PageMap::scan_dirty` call.
+#[derive(Clone, Debug, Default)]
+pub struct DirtyScanResult {
+    /// Every page that matched `DirtyScan::categories_mask`, up to `DirtyScan::max_pages`.
+    pub dirty_pages: Vec<DirtyPage>,
+    /// The number of pages actually walked to produce `dirty_pages` (useful for building a
+    /// per-iteration dirty-page histogram alongside the raw counts).
+    pub pages_scanned: u64,
+}
+
+// The rest of this file is the glue needed to call the `PAGEMAP_SCAN` ioctl directly, since
+// neither `libc` nor the kernel headers on most systems expose it yet.
+
+/// Mirrors the kernel's `struct page_region` (one entry per contiguous run of matching pages).
+#[derive(Copy, Clone, Debug, Default)]
+#[repr(C)]
+struct PageRegion {
+    start: u64,
+    end: u64,
+    categories: u64,
+}
+
+/// Mirrors the kernel's `struct pm_scan_arg`, the argument to the `PAGEMAP_SCAN` ioctl.
+#[derive(Copy, Clone, Debug)]
+#[repr(C)]
+struct PmScanArg {
+    size: u64,
+    flags: u64,
+    start: u64,
+    end: u64,
+    walk_end: u64,
+    vec: u64,
+    vec_len: u64,
+    max_pages: u64,
+    category_inverted: u64,
+    category_mask: u64,
+    category_anyof_mask: u64,
+    return_mask: u64,
+}
+
+/// `_IOWR('f', 16, struct pm_scan_arg)`, computed by hand since this ioctl is too new to be in
+/// most vendored `linux/fs.h` headers.
+const PAGEMAP_SCAN: libc::c_ulong = {
+    const IOC_WRITE: u64 = 1;
+    const IOC_READ: u64 = 2;
+    const TYPE: u64 = b'f' as u64;
+    const NR: u64 = 16;
+    let size = std::mem::size_of::<PmScanArg>() as u64;
+    (((IOC_READ | IOC_WRITE) << 30) | (TYPE << 8) | NR | (size << 16)) as libc::c_ulong
+};
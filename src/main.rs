@@ -1,9 +1,13 @@
 //! Attempts to allocate all free memory and then tests how contiguous that memory is by reading
-//! the pagemap.
+//! the pagemap, by auto-discovering the process's own mapped regions from `/proc/self/maps`
+//! rather than relying on the fixed address the allocation above was mapped at.
 
 use std::{collections::BTreeMap, io};
 
-use frag_test::{PageMap, PAGE_SIZE};
+use frag_test::{
+    decompose_into_buddy_orders, KPageCount, KPageFlags, PageLocation, PageMap, MAX_ORDER,
+    PAGE_SIZE, VSYSCALL_SECTION_START,
+};
 
 const PAGEMAP: &str = "/proc/self/pagemap";
 
@@ -23,7 +27,7 @@ fn main() -> io::Result<()> {
     mmap_populate(avail_bytes);
 
     // Read pagemap to see how contiguous our memory is.
-    process_allocated_mem(avail_bytes)
+    process_allocated_mem()
 }
 
 fn available_bytes() -> io::Result<usize> {
@@ -69,47 +73,189 @@ fn mmap_populate(bytes: usize) {
     }
 }
 
-fn process_allocated_mem(bytes: usize) -> io::Result<()> {
+fn process_allocated_mem() -> io::Result<()> {
     let mut pagemap = PageMap::new(std::fs::File::open(PAGEMAP)?);
 
-    let mut contig = Vec::new();
-    let mut start = 0;
-    let mut prev = 0;
+    let regions = pagemap.regions()?;
 
-    for page in pagemap
-        .get_by_range(MMAP_ADDR, MMAP_ADDR + (bytes as u64))?
-        .into_iter()
+    // Runs of contiguous PFNs among resident pages.
+    let mut present_contig: Vec<(u64, u64)> = Vec::new();
+    let mut present_run: Option<(u64, u64)> = None;
+
+    // Runs of contiguous swap offsets, on the same swap device, among swapped-out pages.
+    let mut swapped_contig: Vec<(u8, u64, u64)> = Vec::new();
+    let mut swapped_run: Option<(u8, u64, u64)> = None;
+
+    let mut hidden_pages = 0u64;
+
+    for region in regions
+        .iter()
+        .filter(|r| r.is_anonymous_private() && r.write)
+        .filter(|r| r.start != VSYSCALL_SECTION_START)
     {
-        if page.pfn() == 0 {
-            return Err(io::Error::new(
-                io::ErrorKind::PermissionDenied,
-                "Need to run as root to see PFNs",
-            ));
-        }
+        // Each region starts fresh runs: the last page of one region is not physically adjacent
+        // to the first page of the next just because they happen to be virtually adjacent.
+        flush(&mut present_run, &mut present_contig);
+        flush(&mut swapped_run, &mut swapped_contig);
 
-        if page.pfn() != prev + 1 {
-            contig.push((start, prev));
-            start = page.pfn();
+        for page in pagemap.get_by_range(region.start, region.end)?.into_iter() {
+            match page.location() {
+                PageLocation::Present(0) => {
+                    // Present, but the PFN is hidden because we lack CAP_SYS_ADMIN.
+                    hidden_pages += 1;
+                    flush(&mut present_run, &mut present_contig);
+                    flush(&mut swapped_run, &mut swapped_contig);
+                }
+                PageLocation::Present(pfn) => {
+                    flush(&mut swapped_run, &mut swapped_contig);
+                    present_run = Some(match present_run {
+                        Some((start, prev)) if pfn == prev + 1 => (start, pfn),
+                        run => {
+                            if let Some(run) = run {
+                                present_contig.push(run);
+                            }
+                            (pfn, pfn)
+                        }
+                    });
+                }
+                PageLocation::Swapped { type_, offset } => {
+                    flush(&mut present_run, &mut present_contig);
+                    swapped_run = Some(match swapped_run {
+                        Some((t, start, prev)) if t == type_ && offset == prev + 1 => {
+                            (t, start, offset)
+                        }
+                        run => {
+                            if let Some(run) = run {
+                                swapped_contig.push(run);
+                            }
+                            (type_, offset, offset)
+                        }
+                    });
+                }
+                PageLocation::NotMapped => {
+                    flush(&mut present_run, &mut present_contig);
+                    flush(&mut swapped_run, &mut swapped_contig);
+                }
+            }
         }
 
-        prev = page.pfn();
+        flush(&mut present_run, &mut present_contig);
+        flush(&mut swapped_run, &mut swapped_contig);
     }
 
-    //println!("{:?}", contig);
+    if hidden_pages > 0 {
+        println!(
+            "Note: {} present pages had hidden PFNs; run as root to see them\n",
+            hidden_pages
+        );
+    }
 
-    println!("Number of contiguous regions: {}\n", contig.len());
-    //println!(
-    //    "{:?}",
-    //    contig.iter().map(|(s, e)| e - s + 1).collect::<Vec<_>>()
-    //);
+    println!("Number of resident contiguous regions: {}\n", present_contig.len());
     println!(
         "{:#?}",
-        categorize(&contig.iter().map(|(s, e)| e - s + 1).collect::<Vec<_>>())
+        categorize(
+            &present_contig
+                .iter()
+                .map(|(s, e)| e - s + 1)
+                .collect::<Vec<_>>()
+        )
+    );
+    println!("{:#?}", categorize_by_kind(&present_contig)?);
+
+    let (block_counts, frag_index) = buddy_order_report(&present_contig);
+    println!("\nBuddy-order histogram (blocks per order):\n{:#?}", block_counts);
+    println!("\nExternal-fragmentation index (fraction of resident pages in a run of at least this order):");
+    println!(
+        "(note: 1 GiB hugetlbfs pages are carved out of CMA/bootmem, not the buddy allocator, \
+         so they're out of scope for this histogram regardless of contiguity)"
+    );
+    for (order, frac) in frag_index {
+        println!(
+            "  order {:>2} ({:>6} pages, {}): {:.1}%",
+            order,
+            1u64 << order,
+            human_order_size(order),
+            frac * 100.0
+        );
+    }
+
+    println!(
+        "\nNumber of contiguous swapped-out regions: {}\n",
+        swapped_contig.len()
+    );
+    println!(
+        "{:#?}",
+        categorize(
+            &swapped_contig
+                .iter()
+                .map(|(_, s, e)| e - s + 1)
+                .collect::<Vec<_>>()
+        )
     );
 
     Ok(())
 }
 
+/// Close out an in-progress run, pushing it onto `contig` if there was one.
+fn flush<T>(run: &mut Option<T>, contig: &mut Vec<T>) {
+    if let Some(run) = run.take() {
+        contig.push(run);
+    }
+}
+
+/// Summarize physical contiguity the way the buddy allocator would: how many blocks of each
+/// order the resident runs decompose into, and, per order, what fraction of all resident pages
+/// sit in a block of at least that order (i.e. hugepage-backing potential).
+///
+/// The fraction is derived from the same alignment-aware `decompose_into_buddy_orders` blocks
+/// used for `block_counts`, not from raw run length: a run can be many pages long and still fail
+/// to contain a high-order block if it starts at a misaligned PFN.
+fn buddy_order_report(contig: &[(u64, u64)]) -> (BTreeMap<u32, usize>, BTreeMap<u32, f64>) {
+    let mut block_counts = BTreeMap::new();
+    let mut pages_at_or_above = vec![0u64; (MAX_ORDER + 1) as usize];
+    let mut total_pages = 0u64;
+
+    for (start, end) in contig.iter() {
+        total_pages += end - start + 1;
+
+        for order in decompose_into_buddy_orders(*start, *end) {
+            *block_counts.entry(order).or_insert(0) += 1;
+
+            let size = 1u64 << order;
+            for pages in pages_at_or_above.iter_mut().take(order as usize + 1) {
+                *pages += size;
+            }
+        }
+    }
+
+    let mut frag_index = BTreeMap::new();
+    for order in 0..=MAX_ORDER {
+        frag_index.insert(
+            order,
+            if total_pages == 0 {
+                0.0
+            } else {
+                pages_at_or_above[order as usize] as f64 / total_pages as f64
+            },
+        );
+    }
+
+    (block_counts, frag_index)
+}
+
+/// A human-readable label for the memory size an order-`order` block backs, for the one order
+/// that lines up with a well-known hugepage size on x86-64 within the buddy allocator's range.
+///
+/// 1 GiB hugetlbfs pages (order 18) never show up here: they're carved out of CMA or reserved at
+/// boot rather than assembled from buddy free lists, so they're out of scope for this histogram
+/// no matter how contiguous resident memory is.
+fn human_order_size(order: u32) -> &'static str {
+    match order {
+        9 => "2 MiB huge page",
+        _ => "no standard hugepage size",
+    }
+}
+
 fn categorize(contig: &[u64]) -> BTreeMap<u64, usize> {
     let mut categorized = BTreeMap::new();
 
@@ -119,3 +265,49 @@ fn categorize(contig: &[u64]) -> BTreeMap<u64, usize> {
 
     categorized
 }
+
+/// Whether a contiguous run of PFNs is backed by an actual compound (huge) page, or is merely a
+/// coincidence of unrelated order-0 pages landing next to each other.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+enum RunKind {
+    /// The run is (part of) a transparent huge page or hugetlbfs huge page.
+    CompoundHuge,
+    /// The run is made of ordinary pages that just happen to be physically adjacent.
+    Adjacent,
+}
+
+/// Bucket each contiguous run of PFNs by whether it's a genuine huge page and whether any page in
+/// it is shared (mapcount > 1), so the report distinguishes real huge-page-backing from
+/// accidental adjacency.
+///
+/// A run is only `CompoundHuge` if *every* page in it is part of a compound page (head or tail).
+/// Checking just the first PFN isn't enough: a genuine huge page immediately followed by
+/// unrelated order-0 pages forms one longer run whose head is a real `compound_head`, which would
+/// wrongly tag the whole run (including the non-huge tail) as `CompoundHuge`; and a run that lost
+/// its leading pages (e.g. a non-present page broke the contiguous run) can start on a
+/// `compound_tail` with no `compound_head` in range at all, which a head-only check would wrongly
+/// call `Adjacent`.
+fn categorize_by_kind(contig: &[(u64, u64)]) -> io::Result<BTreeMap<(RunKind, bool), usize>> {
+    let mut kpageflags = KPageFlags::open()?;
+    let mut kpagecount = KPageCount::open()?;
+
+    let mut categorized = BTreeMap::new();
+
+    for (start, end) in contig.iter() {
+        let run_flags = kpageflags.get_by_range(*start, *end)?;
+        let kind = if run_flags.iter().all(|f| f.compound()) {
+            RunKind::CompoundHuge
+        } else {
+            RunKind::Adjacent
+        };
+
+        let shared = kpagecount
+            .get_by_range(*start, *end)?
+            .into_iter()
+            .any(|count| count > 1);
+
+        *categorized.entry((kind, shared)).or_insert(0) += 1;
+    }
+
+    Ok(categorized)
+}
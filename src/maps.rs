@@ -0,0 +1,99 @@
+//! Parsing for `/proc/[pid]/maps`, used to discover the regions of a process's address space
+//! without having to know its layout ahead of time.
+
+use std::fs;
+use std::io;
+
+/// One line of `/proc/[pid]/maps`: a single mapped region of virtual memory.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Region {
+    /// The first address in the region (inclusive).
+    pub start: u64,
+    /// The last address in the region (exclusive).
+    pub end: u64,
+    /// Is the region readable?
+    pub read: bool,
+    /// Is the region writable?
+    pub write: bool,
+    /// Is the region executable?
+    pub exec: bool,
+    /// Is the region shared (as opposed to private/copy-on-write)?
+    pub shared: bool,
+    /// The offset into the backing file, if any.
+    pub offset: u64,
+    /// The backing device, as `major:minor`.
+    pub dev: String,
+    /// The backing inode, or `0` if the region isn't file-backed.
+    pub inode: u64,
+    /// The backing file path, or a pseudo-path like `[heap]`/`[stack]`, if any.
+    pub pathname: Option<String>,
+}
+
+impl Region {
+    /// Is this region anonymous (i.e., not backed by a file) and privately mapped? This is the
+    /// kind of region a typical heap/mmap allocation shows up as, and also covers pseudo-regions
+    /// like `[heap]`/`[stack]` that the kernel labels but doesn't back with a file.
+    ///
+    /// Excludes the handful of labeled pseudo-regions that are anonymous/private but aren't a
+    /// process's own data, like `[vvar]`/`[vsyscall]`.
+    pub fn is_anonymous_private(&self) -> bool {
+        self.inode == 0
+            && !self.shared
+            && !matches!(self.pathname.as_deref(), Some("[vvar]") | Some("[vsyscall]"))
+    }
+}
+
+/// Parse `/proc/[pid]/maps` into the list of mapped regions.
+pub fn regions_for_pid(pid: u32) -> io::Result<Vec<Region>> {
+    let contents = fs::read_to_string(format!("/proc/{}/maps", pid))?;
+    parse_maps(&contents)
+}
+
+fn parse_maps(contents: &str) -> io::Result<Vec<Region>> {
+    let mut regions = Vec::new();
+
+    for line in contents.lines() {
+        regions.push(parse_maps_line(line)?);
+    }
+
+    Ok(regions)
+}
+
+fn parse_maps_line(line: &str) -> io::Result<Region> {
+    let bad_line = || io::Error::new(io::ErrorKind::InvalidData, format!("bad maps line: {}", line));
+
+    let mut fields = line.splitn(6, ' ').filter(|s| !s.is_empty());
+
+    let addr_range = fields.next().ok_or_else(bad_line)?;
+    let perms = fields.next().ok_or_else(bad_line)?;
+    let offset = fields.next().ok_or_else(bad_line)?;
+    let dev = fields.next().ok_or_else(bad_line)?;
+    let inode = fields.next().ok_or_else(bad_line)?;
+    let pathname = fields.next().map(|s| s.trim_start().to_owned());
+
+    let (start, end) = addr_range.split_once('-').ok_or_else(bad_line)?;
+    let start = u64::from_str_radix(start, 16).map_err(|_| bad_line())?;
+    let end = u64::from_str_radix(end, 16).map_err(|_| bad_line())?;
+
+    let mut perm_chars = perms.chars();
+    let read = perm_chars.next() == Some('r');
+    let write = perm_chars.next() == Some('w');
+    let exec = perm_chars.next() == Some('x');
+    let shared = perm_chars.next() == Some('s');
+
+    let offset = u64::from_str_radix(offset, 16).map_err(|_| bad_line())?;
+    let inode = inode.parse::<u64>().map_err(|_| bad_line())?;
+
+    Ok(Region {
+        start,
+        end,
+        read,
+        write,
+        exec,
+        shared,
+        offset,
+        dev: dev.to_owned(),
+        inode,
+        pathname: pathname.filter(|p| !p.is_empty()),
+    })
+}